@@ -1,8 +1,30 @@
 use serde_json::{json, Value};
 use std::env;
 use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 use wasi_nn::{self, GraphExecutionContext};
 
+// Why generation stopped, carried out of the compute loop so both the
+// interactive mode and the JSON output can report it.
+#[derive(Clone, Copy)]
+enum FinishReason {
+    Stop,
+    Length,
+    PromptTooLong,
+    Error,
+}
+
+impl FinishReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::PromptTooLong => "prompt_too_long",
+            FinishReason::Error => "error",
+        }
+    }
+}
+
 fn read_input() -> String {
     loop {
         let mut answer = String::new();
@@ -15,6 +37,40 @@ fn read_input() -> String {
     }
 }
 
+// Parse a floating-point option, optionally bounding it to an inclusive range.
+// Reports a clear error and exits instead of panicking on malformed input.
+fn parse_f64_option(name: &str, val: &str, min: f64, max: f64) -> f64 {
+    match val.parse::<f64>() {
+        Ok(num) if num >= min && num <= max => num,
+        Ok(num) => {
+            eprintln!(
+                "[ERROR] {} must be between {} and {}, got {}",
+                name, min, max, num
+            );
+            std::process::exit(1);
+        }
+        Err(_) => {
+            eprintln!("[ERROR] {} must be a number, got '{}'", name, val);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Parse an integer option bounded below by `min`.
+fn parse_i64_option(name: &str, val: &str, min: i64) -> i64 {
+    match val.parse::<i64>() {
+        Ok(num) if num >= min => num,
+        Ok(num) => {
+            eprintln!("[ERROR] {} must be >= {}, got {}", name, min, num);
+            std::process::exit(1);
+        }
+        Err(_) => {
+            eprintln!("[ERROR] {} must be an integer, got '{}'", name, val);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn get_options_from_env() -> Value {
     let mut options = json!({});
     match env::var("enable_log") {
@@ -57,6 +113,36 @@ fn get_options_from_env() -> Value {
         Ok(val) => options["repeat-penalty"] = serde_json::from_str(val.as_str()).unwrap(),
         _ => (),
     }
+    match env::var("top_p") {
+        Ok(val) => options["top-p"] = json!(parse_f64_option("top_p", &val, 0.0, 1.0)),
+        _ => (),
+    }
+    match env::var("top_k") {
+        Ok(val) => options["top-k"] = json!(parse_i64_option("top_k", &val, 0)),
+        _ => (),
+    }
+    match env::var("seed") {
+        Ok(val) => options["seed"] = json!(parse_i64_option("seed", &val, i64::MIN)),
+        _ => (),
+    }
+    match env::var("presence_penalty") {
+        Ok(val) => {
+            options["presence-penalty"] =
+                json!(parse_f64_option("presence_penalty", &val, -2.0, 2.0))
+        }
+        _ => (),
+    }
+    match env::var("frequency_penalty") {
+        Ok(val) => {
+            options["frequency-penalty"] =
+                json!(parse_f64_option("frequency_penalty", &val, -2.0, 2.0))
+        }
+        _ => (),
+    }
+    match env::var("grammar") {
+        Ok(val) => options["grammar"] = json!(val),
+        _ => (),
+    }
     match env::var("threads") {
         Ok(val) => options["threads"] = serde_json::from_str(val.as_str()).unwrap(),
         _ => (),
@@ -72,7 +158,6 @@ fn set_data_to_context(
     context.set_input(0, wasi_nn::TensorType::U8, &[1], &data)
 }
 
-#[allow(dead_code)]
 fn set_metadata_to_context(
     context: &mut GraphExecutionContext,
     data: Vec<u8>,
@@ -108,6 +193,174 @@ fn get_metadata_from_context(context: &GraphExecutionContext) -> Value {
     return serde_json::from_str(&get_data_from_context(context, 1, false)).unwrap();
 }
 
+// Run a single completion against the prompt already set on the context,
+// streaming tokens to stdout for the single-token path unless `json_output` is
+// set. Returns the generated text, the reason generation stopped, and the
+// per-token logprob pairs (`Some` only for single-token runs with `logprobs`).
+fn generate_once(
+    context: &mut GraphExecutionContext,
+    is_compute_single: bool,
+    options: &Value,
+    logprobs: bool,
+    json_output: bool,
+) -> (String, FinishReason, Option<Vec<Value>>) {
+    let mut output = String::new();
+    // Only the single-token path can pair each token with its probability.
+    let mut logprob_pairs = if logprobs && is_compute_single {
+        Some(Vec::new())
+    } else {
+        None
+    };
+    let finish_reason;
+    if is_compute_single {
+        // Compute one token at a time, and get the token using the get_output_single().
+        loop {
+            match context.compute_single() {
+                Ok(_) => (),
+                Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::EndOfSequence)) => {
+                    finish_reason = FinishReason::Stop;
+                    break;
+                }
+                Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::ContextFull)) => {
+                    if !json_output {
+                        println!("[INFO] Context full");
+                    }
+                    finish_reason = FinishReason::Length;
+                    break;
+                }
+                Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::PromptTooLong)) => {
+                    if !json_output {
+                        println!("[INFO] Prompt too long");
+                    }
+                    finish_reason = FinishReason::PromptTooLong;
+                    break;
+                }
+                Err(err) => {
+                    if !json_output {
+                        println!("[ERROR] {}", err);
+                    }
+                    finish_reason = FinishReason::Error;
+                    break;
+                }
+            }
+            // Retrieve the output.
+            let token = get_output_from_context(context, is_compute_single);
+            // Stream raw tokens only when not emitting a JSON object, so a
+            // consumer piping stdout gets the JSON alone.
+            if !json_output {
+                print!("{}", token);
+                io::stdout().flush().unwrap();
+            }
+            output += &token;
+
+            // Pair the token with its log-probability from the metadata tensor.
+            // Keep the entry shape stable by emitting `null` when the backend
+            // does not expose probability data for this step.
+            if let Some(pairs) = logprob_pairs.as_mut() {
+                let metadata = get_metadata_from_context(context);
+                // Indexing a missing key already yields `Value::Null`, keeping
+                // the entry shape stable when the backend omits probabilities.
+                let logprob = metadata["logprob"].clone();
+                pairs.push(json!({
+                    "token": token,
+                    "logprob": logprob,
+                }));
+            }
+        }
+        if !json_output {
+            println!("");
+        }
+    } else {
+        // Blocking: execute the inference.
+        context.compute().unwrap();
+
+        // Retrieve the output.
+        output = get_output_from_context(context, is_compute_single);
+
+        // Print the output if not streaming (and not in JSON mode).
+        if !json_output {
+            if !options["stream-stdout"].as_bool().unwrap() {
+                print!("{}", output.trim());
+            }
+            println!("");
+        }
+
+        // The blocking path never surfaces the stop condition, so derive it
+        // from whether the output hit the predicted/context length limit.
+        finish_reason = blocking_finish_reason(&get_metadata_from_context(context), options);
+    }
+    (output, finish_reason, logprob_pairs)
+}
+
+// The blocking `compute()` path does not raise an end-of-sequence error, so
+// infer the finish reason from the token counts: if the output reached the
+// `n-predict` cap (or the context size when no cap is set) it was truncated by
+// length, otherwise it stopped on an EOS token.
+fn blocking_finish_reason(metadata: &Value, options: &Value) -> FinishReason {
+    let output_tokens = metadata["output_tokens"].as_u64();
+    let limit = options["n-preidct"]
+        .as_u64()
+        .or_else(|| options["ctx-size"].as_u64());
+    match (output_tokens, limit) {
+        (Some(output_tokens), Some(limit)) if output_tokens >= limit => FinishReason::Length,
+        _ => FinishReason::Stop,
+    }
+}
+
+// A single completion collected from one run of the generation loop.
+struct Choice {
+    index: usize,
+    text: String,
+    finish_reason: FinishReason,
+    metadata: Value,
+    // Per-token `{ token, logprob }` pairs captured during single-token
+    // generation, or `None` when logprob capture was not requested or the
+    // blocking `compute()` path was used.
+    logprobs: Option<Vec<Value>>,
+}
+
+// Serialize the collected completions as an OpenAI-style `text_completion`
+// object. This lets the example feed tooling that already speaks the
+// completions schema instead of parsing free-form stdout.
+fn build_text_completion(model: &str, choices: &[Choice]) -> Value {
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    // The prompt is shared across choices; completion tokens accumulate.
+    let prompt_tokens = choices
+        .first()
+        .and_then(|c| c.metadata["input_tokens"].as_u64())
+        .unwrap_or(0);
+    let completion_tokens: u64 = choices
+        .iter()
+        .map(|c| c.metadata["output_tokens"].as_u64().unwrap_or(0))
+        .sum();
+    let choices_json: Vec<Value> = choices
+        .iter()
+        .map(|c| {
+            json!({
+                "index": c.index,
+                "text": c.text,
+                "finish_reason": c.finish_reason.as_str(),
+                "logprobs": c.logprobs,
+            })
+        })
+        .collect();
+    json!({
+        "id": format!("cmpl-{}", created),
+        "object": "text_completion",
+        "created": created,
+        "model": model,
+        "choices": choices_json,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        }
+    })
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let model_name: &str = &args[1];
@@ -122,6 +375,25 @@ fn main() {
         _ => (),
     }
 
+    // When `output_format=openai`, emit the completions for each prompt as one
+    // OpenAI-style `text_completion` JSON object instead of plain text.
+    let openai_output = match env::var("output_format") {
+        Ok(val) => val == "openai",
+        _ => false,
+    };
+
+    // Number of independent completions to generate per prompt (best-of-N).
+    let n_choices: usize = match env::var("n_choices") {
+        Ok(val) => parse_i64_option("n_choices", &val, 1) as usize,
+        _ => 1,
+    };
+
+    // Capture per-token log-probabilities during single-token generation.
+    let logprobs = match env::var("logprobs") {
+        Ok(val) => serde_json::from_str(val.as_str()).unwrap(),
+        _ => false,
+    };
+
     let graph =
         wasi_nn::GraphBuilder::new(wasi_nn::GraphEncoding::Ggml, wasi_nn::ExecutionTarget::AUTO)
             .config(options.to_string())
@@ -138,15 +410,51 @@ fn main() {
     // Otherwise, enter interactive mode.
     if args.len() >= 3 {
         let prompt = &args[2];
-        println!("Prompt:\n{}", prompt);
-        let tensor_data = prompt.as_bytes().to_vec();
-        context
-            .set_input(0, wasi_nn::TensorType::U8, &[1], &tensor_data)
-            .unwrap();
-        println!("Response:");
-        context.compute().unwrap();
-        let output = get_output_from_context(&context, false);
-        println!("{}", output.trim());
+        // Suppress human-oriented headers when emitting a bare JSON object.
+        if !openai_output {
+            println!("Prompt:\n{}", prompt);
+            println!("Response:");
+        }
+
+        // Generate `n_choices` completions from the single prompt, just like the
+        // interactive path, so best-of-N works without relaunching the binary.
+        let mut choices: Vec<Choice> = Vec::new();
+        for index in 0..n_choices {
+            // Re-set the prompt for each run; the single-token path also needs a
+            // fresh context via fini_single() between samples.
+            set_data_to_context(&mut context, prompt.as_bytes().to_vec()).unwrap();
+
+            // Offset the seed per choice so repeated runs explore different
+            // samples instead of returning identical text.
+            if let Some(base_seed) = options["seed"].as_i64() {
+                let mut run_options = options.clone();
+                run_options["seed"] = json!(base_seed + index as i64);
+                set_metadata_to_context(&mut context, run_options.to_string().into_bytes())
+                    .unwrap();
+            }
+
+            let (output, finish_reason, logprob_pairs) =
+                generate_once(&mut context, is_compute_single, &options, logprobs, openai_output);
+            let metadata = get_metadata_from_context(&context);
+
+            choices.push(Choice {
+                index,
+                text: output.trim().to_string(),
+                finish_reason,
+                metadata,
+                logprobs: logprob_pairs,
+            });
+
+            // Delete context between samples so the next run starts clean.
+            if is_compute_single {
+                context.fini_single().unwrap();
+            }
+        }
+
+        if openai_output {
+            let completion = build_text_completion(model_name, &choices);
+            println!("{}", completion);
+        }
         std::process::exit(0);
     }
 
@@ -167,67 +475,70 @@ fn main() {
 
         // Get the number of input tokens.
         let input_metadata = get_metadata_from_context(&context);
-        if let Some(true) = options["enable-log"].as_bool() {
-            println!("Number of input tokens: {}", input_metadata["input_tokens"]);
+        if !openai_output {
+            if let Some(true) = options["enable-log"].as_bool() {
+                println!("Number of input tokens: {}", input_metadata["input_tokens"]);
+            }
         }
 
-        println!("Answer:");
-
-        let mut output = String::new();
-        if is_compute_single {
-            // Compute one token at a time, and get the token using the get_output_single().
-            loop {
-                match context.compute_single() {
-                    Ok(_) => (),
-                    Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::EndOfSequence)) => {
-                        break;
-                    }
-                    Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::ContextFull)) => {
-                        println!("[INFO] Context full");
-                        break;
-                    }
-                    Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::PromptTooLong)) => {
-                        println!("[INFO] Prompt too long");
-                        break;
-                    }
-                    Err(err) => {
-                        println!("[ERROR] {}", err);
-                        break;
-                    }
+        // Generate `n_choices` independent completions from the same prompt,
+        // varying the seed per run when one is set (best-of-N sampling).
+        let mut choices: Vec<Choice> = Vec::new();
+        for index in 0..n_choices {
+            // Suppress human-oriented headers when emitting a JSON object.
+            if !openai_output {
+                if n_choices > 1 {
+                    println!("Answer ({}/{}):", index + 1, n_choices);
+                } else {
+                    println!("Answer:");
                 }
-                // Retrieve the output.
-                let token = get_output_from_context(&context, is_compute_single);
-                print!("{}", token);
-                io::stdout().flush().unwrap();
-                output += &token;
             }
-            println!("");
-        } else {
-            // Blocking: execute the inference.
-            context.compute().unwrap();
 
-            // Retrieve the output.
-            output = get_output_from_context(&context, is_compute_single);
+            // Re-set the prompt for each run; the single-token path also needs a
+            // fresh context via fini_single() between samples.
+            set_data_to_context(&mut context, saved_prompt.as_bytes().to_vec()).unwrap();
 
-            // Print the output if not streaming.
-            if !options["stream-stdout"].as_bool().unwrap() {
-                print!("{}", output.trim());
+            // Offset the seed per choice so repeated runs explore different
+            // samples instead of returning identical text.
+            if let Some(base_seed) = options["seed"].as_i64() {
+                let mut run_options = options.clone();
+                run_options["seed"] = json!(base_seed + index as i64);
+                set_metadata_to_context(&mut context, run_options.to_string().into_bytes())
+                    .unwrap();
             }
-            println!("");
-        }
 
-        saved_prompt = format!("{} {} ", saved_prompt, output.trim());
+            let (output, finish_reason, logprob_pairs) =
+                generate_once(&mut context, is_compute_single, &options, logprobs, openai_output);
+            let metadata = get_metadata_from_context(&context);
+            if !openai_output {
+                if let Some(true) = options["enable-log"].as_bool() {
+                    println!("Number of input tokens: {}", metadata["input_tokens"]);
+                    println!("Number of output tokens: {}", metadata["output_tokens"]);
+                    println!("Finish reason: {}", finish_reason.as_str());
+                }
+            }
 
-        // Retrieve the output metadata.
-        let metadata = get_metadata_from_context(&context);
-        if let Some(true) = options["enable-log"].as_bool() {
-            println!("Number of input tokens: {}", metadata["input_tokens"]);
-            println!("Number of output tokens: {}", metadata["output_tokens"]);
+            choices.push(Choice {
+                index,
+                text: output.trim().to_string(),
+                finish_reason,
+                metadata,
+                logprobs: logprob_pairs,
+            });
+
+            // Delete context between samples so the next run starts clean.
+            if is_compute_single {
+                context.fini_single().unwrap();
+            }
         }
 
-        // Delete context.
-        if is_compute_single {
-            context.fini_single().unwrap();
+        // Continue the conversation with the first sample.
+        saved_prompt = format!("{} {} ", saved_prompt, choices[0].text);
+
+        // Emit the completions as an OpenAI-style JSON object when requested.
+        if openai_output {
+            let completion = build_text_completion(model_name, &choices);
+            println!("{}", completion);
         }
     }
 }